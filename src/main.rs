@@ -8,7 +8,8 @@ use std::fs::File;
 use std::io::Write;
 
 use image::{Pixel, ImageBuffer, Rgba};
-use rand::Rng;
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
 use rayon::prelude::*;
 
 
@@ -48,7 +49,7 @@ impl std::ops::Deref for ColorTriangle {
 }
 
 impl Triangle {
-    fn random(width: u32, height: u32) -> Self {
+    fn random(rng: &mut impl Rng, width: u32, height: u32) -> Self {
         const PAD: i32 = 1;
 
         let width = width as i32;
@@ -56,7 +57,6 @@ impl Triangle {
         let hw = width / 5;
         let hh = height / 5;
 
-        let mut rng = rand::thread_rng();
         let a = Point {
             x: rng.gen_range(-hw, width - PAD),
             y: rng.gen_range(-hh, height + hw),
@@ -72,14 +72,29 @@ impl Triangle {
         Self { a, b, c }
     }
 
-    fn contains(&self, point: Point) -> bool {
-        fn orient2d(a: Point, b: Point, c: Point) -> i32 {
-            (b.x - a.x) * (c.y - a.y) - (b.y - a.y) * (c.x - a.x)
+    /// Iterate the pixels covered by this triangle, clipped to a `w` by `h`
+    /// image, using the top-left fill rule so that pixels on an edge shared
+    /// between two adjacent triangles are assigned to exactly one of them.
+    fn rasterize(&self, w: u32, h: u32) -> Coverage {
+        let (x0, y0, x1, y1) = self.bounding(w, h);
+        let origin = Point { x: x0, y: y0 };
+
+        let e0 = EdgeFunction::new(self.a, self.b, origin);
+        let e1 = EdgeFunction::new(self.b, self.c, origin);
+        let e2 = EdgeFunction::new(self.c, self.a, origin);
+
+        Coverage {
+            x: x0,
+            y: y0,
+            x0,
+            x1,
+            y1,
+            val: [e0.value, e1.value, e2.value],
+            row_start: [e0.value, e1.value, e2.value],
+            step_x: [e0.step_x, e1.step_x, e2.step_x],
+            step_y: [e0.step_y, e1.step_y, e2.step_y],
+            top_left: [e0.top_left, e1.top_left, e2.top_left],
         }
-        let w0 = orient2d(self.a, self.b, point);
-        let w1 = orient2d(self.b, self.c, point);
-        let w2 = orient2d(self.c, self.a, point);
-        w0 >= 0 && w1 >= 0 && w2 >= 0
     }
 
     fn bounding(&self, w: u32, h: u32) -> (i32, i32, i32, i32) {
@@ -97,9 +112,93 @@ impl Triangle {
     }
 }
 
+fn orient2d(a: Point, b: Point, c: Point) -> i32 {
+    (b.x - a.x) * (c.y - a.y) - (b.y - a.y) * (c.x - a.x)
+}
+
+/// The value of `orient2d(a, b, p)` at some point `p`, along with how much
+/// that value changes when `p.x` or `p.y` is stepped by one, so a scanline
+/// walk can add increments instead of recomputing the orientation products.
+struct EdgeFunction {
+    value: i32,
+    step_x: i32,
+    step_y: i32,
+    top_left: bool,
+}
+
+impl EdgeFunction {
+    fn new(a: Point, b: Point, origin: Point) -> Self {
+        let dx = b.x - a.x;
+        let dy = b.y - a.y;
+        EdgeFunction {
+            value: orient2d(a, b, origin),
+            step_x: -dy,
+            step_y: dx,
+            top_left: dy < 0 || (dy == 0 && dx < 0),
+        }
+    }
+}
+
+/// Iterator over the pixels a `Triangle` covers within its bounding box,
+/// produced by `Triangle::rasterize`. Walks the three edge functions
+/// row by row, stepping each by its precomputed x/y increment instead of
+/// re-evaluating `orient2d` per pixel.
+struct Coverage {
+    x: i32,
+    y: i32,
+    x0: i32,
+    x1: i32,
+    y1: i32,
+    val: [i32; 3],
+    row_start: [i32; 3],
+    step_x: [i32; 3],
+    step_y: [i32; 3],
+    top_left: [bool; 3],
+}
+
+impl Iterator for Coverage {
+    type Item = Point;
+
+    fn next(&mut self) -> Option<Point> {
+        loop {
+            if self.y >= self.y1 {
+                return None;
+            }
+            if self.x >= self.x1 {
+                self.x = self.x0;
+                self.y += 1;
+                for i in 0..3 {
+                    self.row_start[i] += self.step_y[i];
+                    self.val[i] = self.row_start[i];
+                }
+                continue;
+            }
+
+            let point = Point { x: self.x, y: self.y };
+            let inside = (0..3).all(|i| {
+                self.val[i] > 0 || (self.val[i] == 0 && self.top_left[i])
+            });
+            for i in 0..3 {
+                self.val[i] += self.step_x[i];
+            }
+            self.x += 1;
+
+            if inside {
+                return Some(point);
+            }
+        }
+    }
+}
+
 #[derive(Debug)]
 enum Error {
     MissingInput,
+    InvalidSeed(String),
+    InvalidFormat(String),
+    InvalidDownscale(String),
+    InvalidFilter(String),
+    InvalidLayerHeight(String),
+    InvalidRefineIters(String),
     TriangulationFailed,
     IoError(::std::io::Error),
     ImageError(image::ImageError),
@@ -157,15 +256,349 @@ impl Svg {
             triangle.triangle.c.y = (triangle.triangle.c.y as f32 * sy) as i32;
         }
     }
+
+    /// Rasterize the stored triangles into a fresh `width` by `height`
+    /// buffer, scaling vertices up from this `Svg`'s own dimensions the
+    /// same way `scale` does, so the PNG matches the SVG at any resolution.
+    fn render_to_image(&self, width: u32, height: u32) -> Image {
+        let mut image = Image::new(width, height);
+        let background = *Rgba::from_slice(&self.background);
+        for (_x, _y, p) in image.enumerate_pixels_mut() {
+            *p = background;
+        }
+
+        let sx = width as f32 / self.width as f32;
+        let sy = height as f32 / self.height as f32;
+
+        for triangle in &self.triangles {
+            let scaled = ColorTriangle {
+                triangle: Triangle {
+                    a: Point {
+                        x: (triangle.a.x as f32 * sx) as i32,
+                        y: (triangle.a.y as f32 * sy) as i32,
+                    },
+                    b: Point {
+                        x: (triangle.b.x as f32 * sx) as i32,
+                        y: (triangle.b.y as f32 * sy) as i32,
+                    },
+                    c: Point {
+                        x: (triangle.c.x as f32 * sx) as i32,
+                        y: (triangle.c.y as f32 * sy) as i32,
+                    },
+                },
+                color: triangle.color,
+            };
+            rasterize_triangle(&mut image, scaled);
+        }
+
+        image
+    }
+}
+
+fn rasterize_triangle(image: &mut Image, triangle: ColorTriangle) {
+    let (w, h) = image.dimensions();
+    let color = Rgba::from_slice(&triangle.color);
+    for Point { x, y } in triangle.rasterize(w, h) {
+        let mut p = image.get_pixel_mut(x as u32, y as u32);
+        p.blend(color);
+    }
+}
+
+fn save_png(image: &Image, filename: &AsRef<Path>) -> Result<(), Error> {
+    let file = File::create(filename).map_err(Error::IoError)?;
+    image::png::PNGEncoder::new(file)
+        .encode(image, image.width(), image.height(), image::ColorType::RGBA(8))
+        .map_err(Error::IoError)
+}
+
+struct StlFacet {
+    normal: [f32; 3],
+    vertices: [[f32; 3]; 3],
+}
+
+fn cross(a: [f32; 3], b: [f32; 3]) -> [f32; 3] {
+    [
+        a[1] * b[2] - a[2] * b[1],
+        a[2] * b[0] - a[0] * b[2],
+        a[0] * b[1] - a[1] * b[0],
+    ]
+}
+
+fn sub(a: [f32; 3], b: [f32; 3]) -> [f32; 3] {
+    [a[0] - b[0], a[1] - b[1], a[2] - b[2]]
+}
+
+fn normalize(v: [f32; 3]) -> [f32; 3] {
+    let len = (v[0] * v[0] + v[1] * v[1] + v[2] * v[2]).sqrt();
+    if len == 0.0 {
+        [0.0, 0.0, 0.0]
+    } else {
+        [v[0] / len, v[1] / len, v[2] / len]
+    }
+}
+
+fn facet(a: [f32; 3], b: [f32; 3], c: [f32; 3]) -> StlFacet {
+    StlFacet {
+        normal: normalize(cross(sub(b, a), sub(c, a))),
+        vertices: [a, b, c],
+    }
+}
+
+/// Extrude each stored triangle into a thin triangular prism: a top face at
+/// its z-height, a bottom face at z=0, and three side quads (two facets
+/// each) connecting them. Later-inserted triangles sit higher, so the mesh
+/// mirrors the painting order of the mosaic.
+fn stl_facets(svg: &Svg, layer_height: f32) -> Vec<StlFacet> {
+    let mut facets = Vec::with_capacity(svg.triangles.len() * 8);
+    for (i, triangle) in svg.triangles.iter().enumerate() {
+        let z = (i + 1) as f32 * layer_height;
+        let top = [
+            [triangle.a.x as f32, triangle.a.y as f32, z],
+            [triangle.b.x as f32, triangle.b.y as f32, z],
+            [triangle.c.x as f32, triangle.c.y as f32, z],
+        ];
+        let bottom = [
+            [triangle.a.x as f32, triangle.a.y as f32, 0.0],
+            [triangle.b.x as f32, triangle.b.y as f32, 0.0],
+            [triangle.c.x as f32, triangle.c.y as f32, 0.0],
+        ];
+
+        facets.push(facet(top[0], top[1], top[2]));
+        facets.push(facet(bottom[0], bottom[2], bottom[1]));
+
+        for &(p0, p1) in &[(0, 1), (1, 2), (2, 0)] {
+            facets.push(facet(bottom[p0], bottom[p1], top[p1]));
+            facets.push(facet(bottom[p0], top[p1], top[p0]));
+        }
+    }
+    facets
+}
+
+fn write_f32(f: &mut File, v: f32) -> ::std::io::Result<()> {
+    f.write_all(&v.to_bits().to_le_bytes())
+}
+
+/// Write the mosaic's extruded prisms as binary STL: an 80-byte header, a
+/// little-endian facet count, then per facet a normal, three vertices, and
+/// a zero attribute byte-count.
+fn save_stl(svg: &Svg, layer_height: f32, filename: &AsRef<Path>) -> Result<(), ::std::io::Error> {
+    let facets = stl_facets(svg, layer_height);
+
+    let mut f = File::create(filename)?;
+    f.write_all(&[0u8; 80])?;
+    f.write_all(&(facets.len() as u32).to_le_bytes())?;
+    for facet in &facets {
+        for &v in &facet.normal {
+            write_f32(&mut f, v)?;
+        }
+        for vertex in &facet.vertices {
+            for &v in vertex {
+                write_f32(&mut f, v)?;
+            }
+        }
+        f.write_all(&0u16.to_le_bytes())?;
+    }
+    Ok(())
+}
+
+/// A sidecar record of a `triangulate` run, written alongside the SVG so the
+/// same seed and image reproduce a byte-identical mosaic.
+struct RunMetadata {
+    seed: u64,
+    triangle_count: usize,
+    downscale: u32,
+    total_squared_error: f64,
+    triangles: Vec<ColorTriangle>,
+}
+
+impl RunMetadata {
+    fn save(&self, filename: &AsRef<Path>) -> Result<(), ::std::io::Error> {
+        let mut s = String::new();
+        s.push_str("{\n");
+        s.push_str(&format!("  \"seed\": {},\n", self.seed));
+        s.push_str(&format!("  \"triangle_count\": {},\n", self.triangle_count));
+        s.push_str(&format!("  \"downscale\": {},\n", self.downscale));
+        s.push_str(&format!(
+            "  \"total_squared_error\": {},\n",
+            self.total_squared_error
+        ));
+        s.push_str("  \"triangles\": [\n");
+        for (i, triangle) in self.triangles.iter().enumerate() {
+            s.push_str(&format!(
+                "    {{ \"color\": [{}, {}, {}, {}], \"vertices\": [[{}, {}], [{}, {}], [{}, {}]] }}{}\n",
+                triangle.color[0],
+                triangle.color[1],
+                triangle.color[2],
+                triangle.color[3],
+                triangle.a.x,
+                triangle.a.y,
+                triangle.b.x,
+                triangle.b.y,
+                triangle.c.x,
+                triangle.c.y,
+                if i + 1 < self.triangles.len() { "," } else { "" }
+            ));
+        }
+        s.push_str("  ]\n");
+        s.push_str("}\n");
+        let mut f = File::create(filename)?;
+        f.write_all(s.as_bytes())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum FilterKind {
+    Nearest,
+    Lanczos3,
+}
+
+impl std::str::FromStr for FilterKind {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "nearest" => Ok(FilterKind::Nearest),
+            "lanczos3" => Ok(FilterKind::Lanczos3),
+            _ => Err(()),
+        }
+    }
+}
+
+fn resize_target(image: &Image, width: u32, height: u32, filter: FilterKind) -> Image {
+    match filter {
+        FilterKind::Nearest => {
+            image::imageops::resize(image, width, height, image::FilterType::Nearest)
+        }
+        FilterKind::Lanczos3 => lanczos3_resize(image, width, height),
+    }
+}
+
+const LANCZOS_RADIUS: i32 = 3;
+
+fn sinc(x: f32) -> f32 {
+    if x.abs() < 1e-6 {
+        1.0
+    } else {
+        let px = std::f32::consts::PI * x;
+        px.sin() / px
+    }
+}
+
+fn lanczos3_weight(x: f32) -> f32 {
+    if x.abs() < LANCZOS_RADIUS as f32 {
+        sinc(x) * sinc(x / LANCZOS_RADIUS as f32)
+    } else {
+        0.0
+    }
+}
+
+/// Downsample with a windowed-sinc (Lanczos3) kernel: each output pixel is
+/// a weighted sum of the source pixels within the filter's 3-pixel support
+/// radius, which preserves small features that `FilterType::Nearest` drops.
+fn lanczos3_resize(image: &Image, out_w: u32, out_h: u32) -> Image {
+    let (src_w, src_h) = image.dimensions();
+    let scale_x = src_w as f32 / out_w as f32;
+    let scale_y = src_h as f32 / out_h as f32;
+
+    let mut out = Image::new(out_w, out_h);
+    for oy in 0..out_h {
+        let sy = (oy as f32 + 0.5) * scale_y - 0.5;
+        let sy0 = sy.floor() as i32;
+        for ox in 0..out_w {
+            let sx = (ox as f32 + 0.5) * scale_x - 0.5;
+            let sx0 = sx.floor() as i32;
+
+            let mut sum = [0.0f32; 4];
+            let mut weight_total = 0.0f32;
+            for dy in -(LANCZOS_RADIUS - 1)..=LANCZOS_RADIUS {
+                let iy = (sy0 + dy).max(0).min(src_h as i32 - 1) as u32;
+                let wy = lanczos3_weight(sy - (sy0 + dy) as f32);
+                for dx in -(LANCZOS_RADIUS - 1)..=LANCZOS_RADIUS {
+                    let ix = (sx0 + dx).max(0).min(src_w as i32 - 1) as u32;
+                    let wx = lanczos3_weight(sx - (sx0 + dx) as f32);
+                    let weight = wx * wy;
+                    let p = image.get_pixel(ix, iy).channels();
+                    sum[0] += weight * p[0] as f32;
+                    sum[1] += weight * p[1] as f32;
+                    sum[2] += weight * p[2] as f32;
+                    sum[3] += weight * p[3] as f32;
+                    weight_total += weight;
+                }
+            }
+
+            let channel = |v: f32| (v / weight_total).round().max(0.0).min(255.0) as u8;
+            out.put_pixel(
+                ox,
+                oy,
+                *Rgba::from_slice(&[channel(sum[0]), channel(sum[1]), channel(sum[2]), channel(sum[3])]),
+            );
+        }
+    }
+    out
+}
+
+/// Per-channel weights for the mosaic's error metric, roughly matching
+/// perceived luminance (R/G/B ~ 0.3/0.59/0.11) so the search favors
+/// accuracy in the channels human vision weighs most heavily. Alpha error
+/// is only counted when the source image actually has transparency.
+#[derive(Debug, Clone, Copy)]
+struct ChannelOptions {
+    weights: [f64; 3],
+    include_alpha: bool,
 }
 
-/// Compute the next triangle for the image.
-fn next_triangle(target_image: &Image, current_image: &Image) -> Option<ColorTriangle> {
+impl ChannelOptions {
+    fn new(include_alpha: bool) -> Self {
+        ChannelOptions {
+            weights: [0.3, 0.59, 0.11],
+            include_alpha,
+        }
+    }
+
+    fn squared_error(&self, target: Rgba<u8>, current: Rgba<u8>) -> f64 {
+        let mut error = 0.0;
+        for c in 0..3 {
+            let d = target[c] as f64 - current[c] as f64;
+            error += self.weights[c] * d * d;
+        }
+        if self.include_alpha {
+            let d = target[3] as f64 - current[3] as f64;
+            error += d * d;
+        }
+        error
+    }
+}
+
+fn has_transparency(image: &Image) -> bool {
+    image.pixels().any(|p| p[3] != 255)
+}
+
+fn total_squared_error(target: &Image, current: &Image, channel_options: &ChannelOptions) -> f64 {
+    let mut error = 0.0;
+    for (target_pixel, current_pixel) in target.pixels().zip(current.pixels()) {
+        error += channel_options.squared_error(*target_pixel, *current_pixel);
+    }
+    error
+}
+
+/// Compute the next triangle for the image. `seed` and `iteration` (the
+/// index of this triangle within the mosaic) are combined with the
+/// candidate index to seed each candidate's `StdRng` deterministically, so
+/// the rayon search is reproducible regardless of thread scheduling.
+fn next_triangle(
+    target_image: &Image,
+    current_image: &Image,
+    channel_options: &ChannelOptions,
+    seed: u64,
+    iteration: usize,
+) -> Option<ColorTriangle> {
     (0..N_ITERS)
         .into_par_iter()
-        .flat_map(|_i| {
+        .flat_map(|i| {
+            let sub_seed = seed.wrapping_add((iteration * N_ITERS + i) as u64);
+            let mut rng = StdRng::seed_from_u64(sub_seed);
             let (w, h) = target_image.dimensions();
-            let triangle = Triangle::random(w, h);
+            let triangle = Triangle::random(&mut rng, w, h);
             let (x0, y0, x1, y1) = triangle.bounding(w, h);
 
             let cap = (y1 - y0) as usize + (x1 - x0) as usize;
@@ -177,16 +610,12 @@ fn next_triangle(target_image: &Image, current_image: &Image) -> Option<ColorTri
             }
             let mut pixels = Vec::with_capacity(cap);
             let mut avg_pixel = [0, 0, 0];
-            for y in y0..y1 {
-                for x in x0..x1 {
-                    if triangle.contains(Point { x, y }) {
-                        let p = target_image.get_pixel(x as u32, y as u32).channels();
-                        avg_pixel[0] += p[0] as usize;
-                        avg_pixel[1] += p[1] as usize;
-                        avg_pixel[2] += p[2] as usize;
-                        pixels.push((x as u32, y as u32));
-                    }
-                }
+            for Point { x, y } in triangle.rasterize(w, h) {
+                let p = target_image.get_pixel(x as u32, y as u32).channels();
+                avg_pixel[0] += p[0] as usize;
+                avg_pixel[1] += p[1] as usize;
+                avg_pixel[2] += p[2] as usize;
+                pixels.push((x as u32, y as u32));
             }
             if pixels.len() == 0 {
                 return None;
@@ -201,37 +630,138 @@ fn next_triangle(target_image: &Image, current_image: &Image) -> Option<ColorTri
                 TRANSPARENCY,
             ];
             let score = {
-                let mut s = 0isize;
+                let mut s = 0.0f64;
                 let c = *Rgba::from_slice(&color);
                 for &(x, y) in &pixels {
-                    let target = target_image.get_pixel(x, y);
+                    let target = *target_image.get_pixel(x, y);
                     let before = *current_image.get_pixel(x, y);
-                    let old_error = {
-                        (target[0] as i16 - before[0] as i16).pow(2) as isize +
-                            (target[1] as i16 - before[1] as i16).pow(2) as isize +
-                            (target[2] as i16 - before[2] as i16).pow(2) as isize
-                    };
+                    let old_error = channel_options.squared_error(target, before);
                     let after = {
                         let mut a = before;
                         a.blend(&c);
                         a
                     };
-                    let new_error = {
-                        (target[0] as i16 - after[0] as i16).pow(2) as isize +
-                            (target[1] as i16 - after[1] as i16).pow(2) as isize +
-                            (target[2] as i16 - after[2] as i16).pow(2) as isize
-                    };
+                    let new_error = channel_options.squared_error(target, after);
                     s += new_error - old_error;
                 }
-                s // / pixels.len() as isize
+                s
             };
             Some((score, ColorTriangle { triangle, color }))
         })
-        .min_by_key(|&(score, _)| score)
+        .min_by(|a, b| a.0.partial_cmp(&b.0).unwrap())
         .map(|(_s, triangle)| triangle)
 }
 
-fn triangulate(image: Image) -> Result<Svg, Error> {
+/// Render the mosaic as `render_to_image` does, but painting `candidate` at
+/// `index` in place of the stored triangle there, so the paint order (and
+/// thus the result of alpha-blending) matches what accepting the mutation
+/// would actually produce. Unlike `render_to_image`, vertices are painted
+/// as stored, not scaled to `width` by `height`.
+fn render_with_substitution(
+    svg: &Svg,
+    index: usize,
+    candidate: ColorTriangle,
+    width: u32,
+    height: u32,
+) -> Image {
+    let mut image = Image::new(width, height);
+    let background = *Rgba::from_slice(&svg.background);
+    for (_x, _y, p) in image.enumerate_pixels_mut() {
+        *p = background;
+    }
+    for (i, triangle) in svg.triangles.iter().enumerate() {
+        let triangle = if i == index { candidate } else { *triangle };
+        rasterize_triangle(&mut image, triangle);
+    }
+    image
+}
+
+/// Propose a mutated copy of `triangle`: either jitter one vertex by a
+/// small random delta, or re-sample its color from the region it covers
+/// in `target_image`.
+fn mutate_triangle(rng: &mut StdRng, triangle: &ColorTriangle, target_image: &Image) -> ColorTriangle {
+    const JITTER: i32 = 4;
+
+    let mut mutated = *triangle;
+    let (w, h) = target_image.dimensions();
+
+    if rng.gen_bool(0.5) {
+        // Don't clamp to the frame: `Triangle::random` deliberately places
+        // vertices outside it, and `rasterize` already clips to the image.
+        let jitter = |rng: &mut StdRng, p: Point| -> Point {
+            Point {
+                x: p.x + rng.gen_range(-JITTER, JITTER + 1),
+                y: p.y + rng.gen_range(-JITTER, JITTER + 1),
+            }
+        };
+        match rng.gen_range(0, 3) {
+            0 => mutated.triangle.a = jitter(rng, mutated.triangle.a),
+            1 => mutated.triangle.b = jitter(rng, mutated.triangle.b),
+            _ => mutated.triangle.c = jitter(rng, mutated.triangle.c),
+        }
+    } else {
+        let mut avg_pixel = [0usize; 3];
+        let mut count = 0usize;
+        for Point { x, y } in mutated.triangle.rasterize(w, h) {
+            let p = target_image.get_pixel(x as u32, y as u32).channels();
+            avg_pixel[0] += p[0] as usize;
+            avg_pixel[1] += p[1] as usize;
+            avg_pixel[2] += p[2] as usize;
+            count += 1;
+        }
+        if count > 0 {
+            mutated.color = [
+                (avg_pixel[0] / count) as u8,
+                (avg_pixel[1] / count) as u8,
+                (avg_pixel[2] / count) as u8,
+                mutated.color[3],
+            ];
+        }
+    }
+
+    mutated
+}
+
+/// Hill-climbing refinement pass: repeatedly mutate a randomly chosen
+/// stored triangle and keep the mutation only if it lowers the mosaic's
+/// total squared error against `image`, so a bad early placement from the
+/// greedy fill can still be corrected.
+fn refine(
+    svg: &mut Svg,
+    image: &Image,
+    channel_options: &ChannelOptions,
+    seed: u64,
+    refine_iters: usize,
+) {
+    if svg.triangles.is_empty() {
+        return;
+    }
+
+    let (w, h) = image.dimensions();
+    let mut rng = StdRng::seed_from_u64(seed.wrapping_add(1));
+    let mut current_error = total_squared_error(image, &svg.render_to_image(w, h), channel_options);
+
+    for _ in 0..refine_iters {
+        let index = rng.gen_range(0, svg.triangles.len());
+        let candidate = mutate_triangle(&mut rng, &svg.triangles[index], image);
+
+        let candidate_image = render_with_substitution(svg, index, candidate, w, h);
+        let candidate_error = total_squared_error(image, &candidate_image, channel_options);
+
+        if candidate_error < current_error {
+            svg.triangles[index] = candidate;
+            current_error = candidate_error;
+        }
+    }
+}
+
+fn triangulate(
+    image: Image,
+    seed: u64,
+    downscale: u32,
+    filter: FilterKind,
+    refine_iters: usize,
+) -> Result<(Svg, RunMetadata), Error> {
     fn avg_color(img: &Image) -> Color {
         let n = {
             let (w, h) = img.dimensions();
@@ -253,27 +783,15 @@ fn triangulate(image: Image) -> Result<Svg, Error> {
         }
     }
 
-    fn rasterize_triangle(image: &mut Image, triangle: ColorTriangle) {
-        let (w, h) = image.dimensions();
-        let (x0, y0, x1, y1) = triangle.bounding(w, h);
-
-        let color = Rgba::from_slice(&triangle.color);
-        for y in y0..y1 {
-            for x in x0..x1 {
-                if triangle.contains(Point { x, y }) {
-                    let mut p = image.get_pixel_mut(x as u32, y as u32);
-                    p.blend(color);
-                }
-            }
-        }
-    }
+    let channel_options = ChannelOptions::new(has_transparency(&image));
 
     let (w, h) = image.dimensions();
-    let downsampled =
-        image::imageops::resize(&image, DOWNSCALE, DOWNSCALE, image::FilterType::Nearest);
+    let downsampled = resize_target(&image, downscale, downscale, filter);
 
-    let mut buffer = image.clone();
-    let background_color = avg_color(&buffer);
+    // The search runs entirely at the downscaled resolution, so `buffer`
+    // must match `downsampled`'s dimensions rather than the source image's.
+    let mut buffer = Image::new(downscale, downscale);
+    let background_color = avg_color(&image);
     fill_with(&mut buffer, background_color);
 
     let mut svg = Svg {
@@ -283,36 +801,159 @@ fn triangulate(image: Image) -> Result<Svg, Error> {
         height: image.height(),
     };
 
-    for _ in 0..NUM_TRIANGLES {
-        let triangle = next_triangle(&downsampled, &buffer).ok_or(
+    for i in 0..NUM_TRIANGLES {
+        let triangle = next_triangle(&downsampled, &buffer, &channel_options, seed, i).ok_or(
             Error::TriangulationFailed,
         )?;
         rasterize_triangle(&mut buffer, triangle);
         svg.triangles.push(triangle);
     }
 
-    let scale_x = w as f32 / DOWNSCALE as f32;
-    let scale_y = h as f32 / DOWNSCALE as f32;
+    let scale_x = w as f32 / downscale as f32;
+    let scale_y = h as f32 / downscale as f32;
     svg.scale(scale_x, scale_y);
 
-    Ok(svg)
+    refine(&mut svg, &image, &channel_options, seed, refine_iters);
+
+    let rendered = svg.render_to_image(w, h);
+    let total_squared_error = total_squared_error(&image, &rendered, &channel_options);
+
+    let metadata = RunMetadata {
+        seed,
+        triangle_count: svg.triangles.len(),
+        downscale,
+        total_squared_error,
+        triangles: svg.triangles.clone(),
+    };
+
+    Ok((svg, metadata))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Format {
+    Png,
+    Svg,
+    Both,
+}
+
+impl std::str::FromStr for Format {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "png" => Ok(Format::Png),
+            "svg" => Ok(Format::Svg),
+            "both" => Ok(Format::Both),
+            _ => Err(()),
+        }
+    }
+}
+
+struct Args {
+    filename: String,
+    seed: u64,
+    format: Format,
+    downscale: u32,
+    filter: FilterKind,
+    layer_height: f32,
+    refine_iters: usize,
+}
+
+const DEFAULT_LAYER_HEIGHT: f32 = 1.0;
+
+/// Parse `<filename> [--seed <u64>] [--format png|svg|both] [--downscale N]
+/// [--filter nearest|lanczos3] [--layer-height F] [--refine-iters N]`. When
+/// no seed is given one is drawn from `thread_rng`; it is still recorded in
+/// the sidecar JSON, so passing it back with `--seed` and the same other
+/// options repeats the run (the JSON does not record the filter or the
+/// refine-iters count). The format defaults to `svg`, downscale to `DOWNSCALE`,
+/// filter to `lanczos3`, layer height to `DEFAULT_LAYER_HEIGHT`, and
+/// refine-iters to `0` (no refinement pass).
+fn parse_args() -> Result<Args, Error> {
+    let mut filename = None;
+    let mut seed = None;
+    let mut format = None;
+    let mut downscale = None;
+    let mut filter = None;
+    let mut layer_height = None;
+    let mut refine_iters = None;
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--seed" => {
+                let value = args.next().ok_or(Error::MissingInput)?;
+                seed = Some(value.parse().map_err(|_| Error::InvalidSeed(value))?);
+            }
+            "--format" => {
+                let value = args.next().ok_or(Error::MissingInput)?;
+                format = Some(value.parse().map_err(|_| Error::InvalidFormat(value))?);
+            }
+            "--downscale" => {
+                let value = args.next().ok_or(Error::MissingInput)?;
+                downscale = Some(value.parse().map_err(|_| Error::InvalidDownscale(value))?);
+            }
+            "--filter" => {
+                let value = args.next().ok_or(Error::MissingInput)?;
+                filter = Some(value.parse().map_err(|_| Error::InvalidFilter(value))?);
+            }
+            "--layer-height" => {
+                let value = args.next().ok_or(Error::MissingInput)?;
+                layer_height = Some(value.parse().map_err(|_| Error::InvalidLayerHeight(value))?);
+            }
+            "--refine-iters" => {
+                let value = args.next().ok_or(Error::MissingInput)?;
+                refine_iters = Some(value.parse().map_err(|_| Error::InvalidRefineIters(value))?);
+            }
+            other => filename = Some(other.to_string()),
+        }
+    }
+
+    Ok(Args {
+        filename: filename.ok_or(Error::MissingInput)?,
+        downscale: downscale.unwrap_or(DOWNSCALE),
+        filter: filter.unwrap_or(FilterKind::Lanczos3),
+        layer_height: layer_height.unwrap_or(DEFAULT_LAYER_HEIGHT),
+        refine_iters: refine_iters.unwrap_or(0),
+        seed: seed.unwrap_or_else(|| rand::thread_rng().gen()),
+        format: format.unwrap_or(Format::Svg),
+    })
 }
 
 fn do_stuff() -> Result<(), Error> {
-    let filename = env::args().nth(1).ok_or(Error::MissingInput)?;
-    let image: Image = image::open(&filename)
+    let args = parse_args()?;
+    let image: Image = image::open(&args.filename)
         .map_err(|e| Error::ImageError(e))?
         .to_rgba();
-    let triangulated = triangulate(image)?;
-    triangulated
-        .save(&format!("out-{}.svg", filename))
+    let (triangulated, metadata) = triangulate(
+        image,
+        args.seed,
+        args.downscale,
+        args.filter,
+        args.refine_iters,
+    )?;
+
+    if args.format == Format::Svg || args.format == Format::Both {
+        triangulated
+            .save(&format!("out-{}.svg", args.filename))
+            .map_err(|e| Error::IoError(e))?;
+    }
+    if args.format == Format::Png || args.format == Format::Both {
+        let rendered = triangulated.render_to_image(triangulated.width, triangulated.height);
+        save_png(&rendered, &format!("out-{}.png", args.filename))?;
+    }
+
+    metadata
+        .save(&format!("out-{}.json", args.filename))
+        .map_err(|e| Error::IoError(e))?;
+    save_stl(&triangulated, args.layer_height, &format!("out-{}.stl", args.filename))
         .map_err(|e| Error::IoError(e))?;
     Ok(())
 }
 
 fn main() {
-    match do_stuff() {
-        Ok(()) => {}
-        _ => unreachable!(),
+    if let Err(e) = do_stuff() {
+        eprintln!("error: {:?}", e);
+        std::process::exit(1);
     }
 }